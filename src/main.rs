@@ -1,122 +1,508 @@
-use std::{io::Cursor, net::SocketAddr};
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::{Context, Result};
 use axum::{
-    extract::{Path, Query, Request},
-    http::{HeaderValue, StatusCode},
+    body::Body,
+    extract::{Path, Query, Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
     middleware::{self, Next},
     response::{IntoResponse, Response},
     routing::get,
     Json, Router,
 };
-use axum_response_cache::CacheLayer;
 use clap::Parser;
 use html_escape::decode_html_entities;
 use image::{DynamicImage, GenericImageView as _, ImageBuffer, Rgba};
 use regex::Regex;
 use reqwest::{header, Method};
+use scraper::{Html, Selector};
 use serde::{Deserialize, Serialize};
-use tokio::net::TcpListener;
+use sha2::{Digest, Sha256};
+use tokio::{net::TcpListener, sync::Mutex};
 use tower_http::cors::CorsLayer;
 use url::Url;
 
-#[derive(Clone, Parser)]
+#[derive(Parser)]
 struct Args {
     #[clap(short, long, default_value = "[::]:3319")]
     listen: SocketAddr,
     #[clap(short, long, default_value = "https://zz.nekok500.com")]
     base_url: Url,
+    /// Number of attempts made against flaky upstreams before giving up.
+    #[clap(long, default_value_t = 3)]
+    retry_attempts: usize,
+    /// Delay between retry attempts, in milliseconds.
+    #[clap(long, default_value_t = 200)]
+    retry_delay_ms: u64,
+}
+
+const RESPONSE_CACHE_LIFESPAN: Duration = Duration::from_secs(3600);
+
+/// Shared application state: the CLI args plus an in-process cache of
+/// upstream-derived response bodies, keyed by a string that already encodes
+/// everything the body varies on (URL, size, resolved format, quality, ...).
+/// Caching at this layer — below content negotiation and conditional-GET —
+/// means a cache hit skips the upstream fetch/resize/encode entirely, while
+/// ETag/Last-Modified are still evaluated fresh per request, so a 304 is
+/// never accidentally served to a client that never saw the asset before.
+struct AppState {
+    args: Args,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+}
+
+#[derive(Clone)]
+struct CacheEntry {
+    body: Vec<u8>,
+    content_type: String,
+    last_modified: Option<HeaderValue>,
+    fetched_at: Instant,
+}
+
+/// Returns the cached entry for `key` if it's still fresh, otherwise runs
+/// `fetch`, caches its result, and returns that.
+async fn cached_or_fetch<F, Fut>(state: &AppState, key: String, fetch: F) -> Result<CacheEntry, AppError>
+where
+    F: FnOnce() -> Fut,
+    Fut: std::future::Future<Output = Result<(Vec<u8>, String, Option<HeaderValue>), AppError>>,
+{
+    {
+        let cache = state.cache.lock().await;
+        if let Some(entry) = cache.get(&key) {
+            if entry.fetched_at.elapsed() < RESPONSE_CACHE_LIFESPAN {
+                return Ok(entry.clone());
+            }
+        }
+    }
+
+    let (body, content_type, last_modified) = fetch().await?;
+    let entry = CacheEntry {
+        body,
+        content_type,
+        last_modified,
+        fetched_at: Instant::now(),
+    };
+    state.cache.lock().await.insert(key, entry.clone());
+    Ok(entry)
+}
+
+/// Fetches `url`, making up to `args.retry_attempts` attempts in total (at
+/// least one) with a `args.retry_delay_ms` sleep in between. Connection errors
+/// and 5xx/429 responses are retried; any other 4xx is surfaced immediately.
+/// The returned response never has a 4xx or 5xx status; any other status
+/// (e.g. a 3xx that reqwest did not follow) is returned as-is.
+async fn get_with_retry(url: &str, args: &Args) -> Result<reqwest::Response, AppError> {
+    let attempts = args.retry_attempts.max(1);
+    let mut last_err = None;
+    for attempt in 1..=attempts {
+        match reqwest::get(url).await {
+            Ok(response) => {
+                let status = response.status();
+                if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+                    last_err = Some(AppError::from_response(response));
+                } else if status.is_client_error() {
+                    return Err(AppError::from_response(response));
+                } else {
+                    return Ok(response);
+                }
+            }
+            Err(err) if err.is_connect() || err.is_timeout() => {
+                last_err = Some(AppError::from(err))
+            }
+            Err(err) => return Err(AppError::from(err)),
+        }
+        if attempt < attempts {
+            tokio::time::sleep(Duration::from_millis(args.retry_delay_ms)).await;
+        }
+    }
+    Err(last_err.unwrap())
+}
+
+enum AppError {
+    /// An upstream (or locally-determined) error with an explicit status code
+    /// to hand back to the caller, e.g. a zaiko 404 or a rate-limiting 429.
+    Status {
+        status: StatusCode,
+        message: String,
+        retry_after: Option<HeaderValue>,
+    },
+    /// Anything else, surfaced as a generic 500.
+    Other(anyhow::Error),
+}
+
+impl AppError {
+    /// Builds a `Status` error from a non-success upstream response,
+    /// forwarding `Retry-After` when the upstream sent one.
+    fn from_response(response: reqwest::Response) -> Self {
+        let status = response.status();
+        let retry_after = response.headers().get(header::RETRY_AFTER).cloned();
+        Self::Status {
+            status,
+            message: format!("upstream returned {status}"),
+            retry_after,
+        }
+    }
 }
 
-struct AppError(anyhow::Error);
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        )
-            .into_response()
+        match self {
+            Self::Status {
+                status,
+                message,
+                retry_after,
+            } => {
+                let mut response = (status, message).into_response();
+                if let Some(retry_after) = retry_after {
+                    response.headers_mut().insert(header::RETRY_AFTER, retry_after);
+                }
+                response
+            }
+            Self::Other(err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Something went wrong: {err}"),
+            )
+                .into_response(),
+        }
     }
 }
 
-impl<E> From<E> for AppError
-where
-    E: Into<anyhow::Error>,
-{
-    fn from(err: E) -> Self {
-        Self(err.into())
+impl From<anyhow::Error> for AppError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::Other(err)
+    }
+}
+
+impl From<reqwest::Error> for AppError {
+    fn from(err: reqwest::Error) -> Self {
+        match err.status() {
+            Some(status) => Self::Status {
+                status,
+                message: err.to_string(),
+                retry_after: None,
+            },
+            None => Self::Other(err.into()),
+        }
     }
 }
 
 #[derive(Serialize, Clone)]
 struct Metadata {
-    owner_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    owner_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date: Option<String>,
 }
 
-async fn event(Path(event_id): Path<usize>) -> Result<Json<Metadata>, AppError> {
-    let first = reqwest::get(format!("https://zaiko.io/event/{}", event_id))
-        .await?
-        .error_for_status()?
-        .text()
-        .await?;
-    let second_url = Regex::new(";url='(.+)'\" />")
-        .unwrap()
-        .captures(&first)
-        .context("first: no context")?
-        .get(1)
-        .unwrap()
-        .as_str();
-
-    let second = reqwest::get(second_url)
-        .await?
-        .error_for_status()?
-        .text()
-        .await?;
+/// Reads the `content` attribute of the first element matching `selector`,
+/// decoding HTML entities. Returns `None` if the page doesn't have it.
+fn meta_content(document: &Html, selector: &str) -> Option<String> {
+    let selector = Selector::parse(selector).ok()?;
+    let content = document.select(&selector).next()?.value().attr("content")?;
+    Some(decode_html_entities(content).to_string())
+}
 
-    let site_name = decode_html_entities(
-        Regex::new(r#"<meta property="og:site_name" content="(.+)" />"#)
+async fn event(
+    Path(event_id): Path<usize>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let entry = cached_or_fetch(&state, format!("event:{event_id}"), || async {
+        let first = get_with_retry(&format!("https://zaiko.io/event/{}", event_id), &state.args)
+            .await?
+            .text()
+            .await?;
+        let second_url = Regex::new(";url='(.+)'\" />")
             .unwrap()
-            .captures(&second)
-            .context("second: og:site_name no context")?
+            .captures(&first)
+            .context("first: no context")?
             .get(1)
             .unwrap()
-            .as_str(),
-    );
-    Ok(Json(Metadata {
-        owner_name: site_name.to_string(),
-    }))
+            .as_str();
+
+        let second_response = get_with_retry(second_url, &state.args).await?;
+        let last_modified = second_response.headers().get(header::LAST_MODIFIED).cloned();
+        let second = second_response.text().await?;
+
+        let document = Html::parse_document(&second);
+        let metadata = Metadata {
+            owner_name: meta_content(&document, r#"meta[property="og:site_name"]"#),
+            title: meta_content(&document, r#"meta[property="og:title"]"#),
+            description: meta_content(&document, r#"meta[property="og:description"]"#),
+            image: meta_content(&document, r#"meta[property="og:image"]"#),
+            date: meta_content(&document, r#"meta[itemprop="startDate"]"#),
+        };
+        let body = serde_json::to_vec(&metadata).context("failed to serialize metadata")?;
+        Ok((body, "application/json".to_string(), last_modified))
+    })
+    .await?;
+
+    Ok(conditional_response(
+        &headers,
+        entry.body,
+        &entry.content_type,
+        entry.last_modified,
+    ))
 }
 
+// 400 is kept in the allowlist (alongside the requested example sizes) so the
+// pre-existing default stays a valid, cacheable size instead of silently
+// shrinking every caller that omits `size`.
+const ALLOWED_SIZES: &[u32] = &[80, 160, 320, 400, 640, 1080];
+const DEFAULT_SIZE: u32 = 400;
+
 #[derive(Deserialize)]
 struct SquareParams {
     u: String,
+    size: Option<u32>,
+    format: Option<String>,
+    quality: Option<u8>,
 }
 
-async fn square(query: Query<SquareParams>) -> Result<Response, AppError> {
+async fn square(
+    query: Query<SquareParams>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
     if !query.u.starts_with("https://media.zaiko.io/") {
         return Ok((StatusCode::BAD_REQUEST, "url not allowed").into_response());
     }
 
-    let image_bytes = reqwest::get(query.u.clone())
-        .await?
-        .error_for_status()?
-        .bytes()
-        .await?
-        .to_vec();
+    let size = query.size.unwrap_or(DEFAULT_SIZE);
+    if !ALLOWED_SIZES.contains(&size) {
+        return Ok((StatusCode::BAD_REQUEST, "size not allowed").into_response());
+    }
 
-    let img = image::load_from_memory(&image_bytes)?;
-    let resized_img = resize_image(&img, 400, 400);
+    let (format, negotiated_from_accept) = match query.format.as_deref() {
+        Some(requested) => match format_from_name(requested) {
+            Some(format) => (format, false),
+            None => return Ok((StatusCode::BAD_REQUEST, "format not allowed").into_response()),
+        },
+        None => (
+            format_from_accept_header(headers.get(header::ACCEPT)).unwrap_or(image::ImageFormat::Png),
+            true,
+        ),
+    };
 
-    let mut buffer = Cursor::new(Vec::new());
-    resized_img.write_to(&mut buffer, image::ImageFormat::Png)?;
+    // The `image` crate's WebP encoder only supports lossless output, so
+    // there's no quality knob to honor. Reject the param instead of silently
+    // ignoring it, same as an unsupported `format`/`size`.
+    if format == image::ImageFormat::WebP && query.quality.is_some() {
+        return Ok((StatusCode::BAD_REQUEST, "quality is not supported for webp").into_response());
+    }
+
+    // The cache key is the *resolved* format, not the raw `Accept` header, so
+    // two clients that negotiate to the same format share an entry and two
+    // clients that negotiate to different formats never collide.
+    let cache_key = format!(
+        "square:{}:{}:{:?}:{:?}",
+        query.u, size, format, query.quality
+    );
+    let entry = cached_or_fetch(&state, cache_key, || async {
+        let upstream = get_with_retry(&query.u, &state.args).await?;
+        let upstream_last_modified = upstream.headers().get(header::LAST_MODIFIED).cloned();
+        let image_bytes = upstream.bytes().await?.to_vec();
+
+        let img = image::load_from_memory(&image_bytes).context("failed to decode image")?;
+        let resized_img = resize_image(&img, size, size);
+        let encoded = encode_image(&resized_img, format, query.quality)?;
+        Ok((
+            encoded,
+            content_type_for(format).to_string(),
+            upstream_last_modified,
+        ))
+    })
+    .await?;
+
+    let mut response = conditional_response(
+        &headers,
+        entry.body,
+        &entry.content_type,
+        entry.last_modified,
+    );
+    if negotiated_from_accept {
+        response
+            .headers_mut()
+            .insert(header::VARY, HeaderValue::from_static("Accept"));
+    }
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct MediaParams {
+    u: String,
+}
+
+async fn media(
+    query: Query<MediaParams>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    if !query.u.starts_with("https://media.zaiko.io/") {
+        return Ok((StatusCode::BAD_REQUEST, "url not allowed").into_response());
+    }
 
-    Ok(Response::builder()
-        .status(StatusCode::OK)
-        .header("Content-Type", "image/png")
-        .body(buffer.into_inner().into())
+    // Unlike `event`/`square`, this is a raw passthrough of whatever size the
+    // upstream asset is, with no resize/encode step to amortize — caching it
+    // would mean buffering arbitrarily large bodies into memory just to save
+    // a future proxy hop, which defeats the point of streaming it straight
+    // through.
+    let upstream = get_with_retry(&query.u, &state.args).await?;
+
+    let mut builder = Response::builder().status(StatusCode::OK);
+    for header_name in [
+        header::CONTENT_TYPE,
+        header::CONTENT_LENGTH,
+        header::LAST_MODIFIED,
+    ] {
+        if let Some(value) = upstream.headers().get(&header_name) {
+            builder = builder.header(header_name, value);
+        }
+    }
+
+    Ok(builder
+        .body(Body::from_stream(upstream.bytes_stream()))
         .unwrap())
 }
 
+fn format_from_name(name: &str) -> Option<image::ImageFormat> {
+    match name.to_ascii_lowercase().as_str() {
+        "png" => Some(image::ImageFormat::Png),
+        "webp" => Some(image::ImageFormat::WebP),
+        "jpeg" | "jpg" => Some(image::ImageFormat::Jpeg),
+        "avif" => Some(image::ImageFormat::Avif),
+        _ => None,
+    }
+}
+
+fn format_from_accept_header(accept: Option<&HeaderValue>) -> Option<image::ImageFormat> {
+    let accept = accept?.to_str().ok()?;
+    if accept.contains("image/avif") {
+        Some(image::ImageFormat::Avif)
+    } else if accept.contains("image/webp") {
+        Some(image::ImageFormat::WebP)
+    } else if accept.contains("image/jpeg") {
+        Some(image::ImageFormat::Jpeg)
+    } else {
+        None
+    }
+}
+
+fn content_type_for(format: image::ImageFormat) -> &'static str {
+    match format {
+        image::ImageFormat::WebP => "image/webp",
+        image::ImageFormat::Jpeg => "image/jpeg",
+        image::ImageFormat::Avif => "image/avif",
+        _ => "image/png",
+    }
+}
+
+fn encode_image(
+    img: &DynamicImage,
+    format: image::ImageFormat,
+    quality: Option<u8>,
+) -> Result<Vec<u8>> {
+    let mut buffer = Cursor::new(Vec::new());
+    let quality = quality.unwrap_or(80).clamp(1, 100);
+    match format {
+        image::ImageFormat::Jpeg => {
+            // JPEG has no alpha channel. Composite onto an opaque white
+            // background first so the transparent padding `resize_image`
+            // adds comes out as white rather than the black bars a bare
+            // alpha-channel drop would leave.
+            let (width, height) = img.dimensions();
+            let mut opaque = ImageBuffer::from_pixel(width, height, Rgba([255, 255, 255, 255]));
+            image::imageops::overlay(&mut opaque, img, 0, 0);
+
+            let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, quality);
+            let rgb = DynamicImage::ImageRgba8(opaque).to_rgb8();
+            DynamicImage::ImageRgb8(rgb).write_with_encoder(encoder)?;
+        }
+        image::ImageFormat::Avif => {
+            let encoder =
+                image::codecs::avif::AvifEncoder::new_with_speed_quality(&mut buffer, 4, quality);
+            img.write_with_encoder(encoder)?;
+        }
+        _ => img.write_to(&mut buffer, format)?,
+    }
+    Ok(buffer.into_inner())
+}
+
+fn compute_etag(bytes: &[u8]) -> String {
+    let hash = Sha256::digest(bytes);
+    format!("\"{:x}\"", hash)
+}
+
+fn etag_matches(if_none_match: Option<&HeaderValue>, etag: &str) -> bool {
+    match if_none_match.and_then(|v| v.to_str().ok()) {
+        Some(value) => value.split(',').any(|v| v.trim() == etag || v.trim() == "*"),
+        None => false,
+    }
+}
+
+fn not_modified_since(
+    if_modified_since: Option<&HeaderValue>,
+    last_modified: Option<&HeaderValue>,
+) -> bool {
+    let if_modified_since = if_modified_since
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok());
+    let last_modified = last_modified
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok());
+    match (if_modified_since, last_modified) {
+        (Some(if_modified_since), Some(last_modified)) => last_modified <= if_modified_since,
+        _ => false,
+    }
+}
+
+fn conditional_response(
+    request_headers: &HeaderMap,
+    body: Vec<u8>,
+    content_type: &str,
+    upstream_last_modified: Option<HeaderValue>,
+) -> Response {
+    let etag = compute_etag(&body);
+    let if_none_match = request_headers.get(header::IF_NONE_MATCH);
+    // Per RFC 7232 §3.3, If-Modified-Since is only considered when the
+    // request has no If-None-Match — a client providing an ETag has already
+    // told us which representation it holds, so that's authoritative.
+    let not_modified = match if_none_match {
+        Some(_) => etag_matches(if_none_match, &etag),
+        None => not_modified_since(
+            request_headers.get(header::IF_MODIFIED_SINCE),
+            upstream_last_modified.as_ref(),
+        ),
+    };
+
+    let mut builder = Response::builder()
+        .status(if not_modified {
+            StatusCode::NOT_MODIFIED
+        } else {
+            StatusCode::OK
+        })
+        .header(header::ETAG, etag);
+    if let Some(last_modified) = upstream_last_modified {
+        builder = builder.header(header::LAST_MODIFIED, last_modified);
+    }
+    if !not_modified {
+        builder = builder.header("Content-Type", content_type.to_string());
+    }
+    builder
+        .body(if not_modified { Vec::new() } else { body }.into())
+        .unwrap()
+}
+
 fn resize_image(img: &DynamicImage, width: u32, height: u32) -> DynamicImage {
     let resized_img = img.resize(width, height, image::imageops::FilterType::Lanczos3);
 
@@ -138,18 +524,40 @@ fn resize_image(img: &DynamicImage, width: u32, height: u32) -> DynamicImage {
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     let args = Args::try_parse()?;
+    let listen = args.listen;
+    let state = Arc::new(AppState {
+        args,
+        cache: Mutex::new(HashMap::new()),
+    });
 
+    // `/zaiko/events/:event_id` and `/square.png` are backed by `AppState`'s
+    // in-process cache (see `cached_or_fetch`) instead of a whole-response
+    // cache layer, because both routes vary on request state a
+    // method+URI-keyed layer can't see: `square` resolves `Accept` into a
+    // concrete format, and both routes support conditional GET. Keying on
+    // the *resolved* format/size/quality (not the raw `Accept` header) means
+    // two clients negotiating the same representation share a cache entry
+    // and two negotiating different ones never collide; storing only the
+    // canonical bytes and Last-Modified (never a 304 itself) means
+    // `conditional_response` still evaluates If-None-Match/If-Modified-Since
+    // fresh per request, so a 304 is never replayed to a client that hasn't
+    // earned one.
+    //
+    // `/media` deliberately doesn't use this cache: it streams the upstream
+    // body straight through, and caching it would mean buffering arbitrarily
+    // large bodies in memory just to save a future proxy hop.
     let app = Router::new()
         .route("/zaiko/events/:event_id", get(event))
         .route("/square.png", get(square))
+        .route("/media", get(media))
         .layer(middleware::from_fn(set_static_cache_control))
-        .layer(CacheLayer::with_lifespan(3600))
         .layer(
             CorsLayer::new()
                 .allow_methods([Method::GET])
                 .allow_origin("https://zaiko.io".parse::<HeaderValue>().unwrap()),
-        );
-    let listener = TcpListener::bind(args.listen).await?;
+        )
+        .with_state(state);
+    let listener = TcpListener::bind(listen).await?;
     axum::serve(listener, app).await?;
 
     Ok(())